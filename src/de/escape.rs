@@ -0,0 +1,73 @@
+use std::borrow::Cow;
+
+/// Decode TeamSpeak ServerQuery escape sequences in a raw byte slice.
+///
+/// Borrows the input unchanged when it contains no backslash, so the common, already-clean
+/// case stays zero-copy. An unrecognized escape (a backslash not followed by one of the known
+/// codes) is passed through verbatim, matching the leniency of the rest of the parser.
+pub(crate) fn unescape(input: &[u8]) -> Cow<'_, [u8]> {
+    if !input.contains(&b'\\') {
+        return Cow::Borrowed(input);
+    }
+
+    let mut out = Vec::with_capacity(input.len());
+    let mut bytes = input.iter().copied();
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            out.push(byte);
+            continue;
+        }
+
+        match bytes.next() {
+            Some(b'\\') => out.push(b'\\'),
+            Some(b'/') => out.push(b'/'),
+            Some(b's') => out.push(b' '),
+            Some(b'p') => out.push(b'|'),
+            Some(b'a') => out.push(0x07),
+            Some(b'b') => out.push(0x08),
+            Some(b'f') => out.push(0x0c),
+            Some(b'n') => out.push(b'\n'),
+            Some(b'r') => out.push(b'\r'),
+            Some(b't') => out.push(b'\t'),
+            Some(b'v') => out.push(0x0b),
+            Some(other) => {
+                out.push(b'\\');
+                out.push(other);
+            }
+            None => out.push(b'\\'),
+        }
+    }
+
+    Cow::Owned(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn borrows_when_there_is_nothing_to_unescape() {
+        match unescape(b"plain") {
+            Cow::Borrowed(bytes) => assert_eq!(bytes, b"plain"),
+            Cow::Owned(_) => panic!("expected a borrowed Cow"),
+        }
+    }
+
+    #[test]
+    fn decodes_every_known_escape() {
+        assert_eq!(
+            &*unescape(b"\\\\\\/\\s\\p\\a\\b\\f\\n\\r\\t\\v"),
+            b"\\/ |\x07\x08\x0c\n\r\t\x0b"
+        );
+    }
+
+    #[test]
+    fn passes_through_unknown_escapes_verbatim() {
+        assert_eq!(&*unescape(b"\\x"), b"\\x");
+    }
+
+    #[test]
+    fn passes_through_a_trailing_lone_backslash() {
+        assert_eq!(&*unescape(b"a\\"), b"a\\");
+    }
+}
@@ -0,0 +1,64 @@
+use crate::error::Result;
+
+/// Splits a ServerQuery list response into its `|`-delimited records.
+///
+/// TeamSpeak never lets a literal `|` through a value -- it is always encoded as the `\p`
+/// escape -- so splitting on a raw `|` byte is always correct and needs no escape-awareness.
+pub(crate) struct Records<'de> {
+    remaining: Option<&'de [u8]>,
+}
+
+impl<'de> Records<'de> {
+    pub(crate) fn new(input: &'de [u8]) -> Self {
+        Self {
+            remaining: if input.is_empty() { None } else { Some(input) },
+        }
+    }
+}
+
+impl<'de> Iterator for Records<'de> {
+    type Item = &'de [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let input = self.remaining.take()?;
+        match input.iter().position(|&byte| byte == b'|') {
+            Some(index) => {
+                let rest = &input[index + 1..];
+                self.remaining = if rest.is_empty() { None } else { Some(rest) };
+                Some(&input[..index])
+            }
+            None => Some(input),
+        }
+    }
+}
+
+/// Lazily deserializes each `|`-delimited record of a ServerQuery list response into a `T`,
+/// one record at a time, instead of buffering every record into a `Vec` up front.
+pub fn from_bytes_seq<'de, T>(input: &'de [u8]) -> impl Iterator<Item = Result<T>> + 'de
+where
+    T: serde::Deserialize<'de> + 'de,
+{
+    Records::new(input).map(crate::from_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_on_every_pipe() {
+        let records: Vec<_> = Records::new(b"a=1|b=2|c=3").collect();
+        assert_eq!(records, [&b"a=1"[..], &b"b=2"[..], &b"c=3"[..]]);
+    }
+
+    #[test]
+    fn a_trailing_pipe_does_not_produce_a_spurious_empty_record() {
+        let records: Vec<_> = Records::new(b"a=1|b=2|").collect();
+        assert_eq!(records, [&b"a=1"[..], &b"b=2"[..]]);
+    }
+
+    #[test]
+    fn empty_input_yields_no_records() {
+        assert_eq!(Records::new(b"").next(), None);
+    }
+}
@@ -1,36 +1,221 @@
+use std::borrow::Cow;
+#[cfg(not(feature = "no_std"))]
 use std::collections::VecDeque;
 
+#[cfg(feature = "no_std")]
+use heapless::Deque;
 use serde::{de, forward_to_deserialize_any};
 
 use super::{
+    super::escape::unescape,
     seq::{ItemKind, PairSeq},
     Pair, Stash,
 };
 use crate::de::Deserializer;
 use crate::error::{Error, Result};
 
-pub(crate) struct PairMap<'de> {
-    pairs: VecDeque<Pair<'de>>,
+/// The pair queue backing a [`PairMap`]: an unbounded [`VecDeque`] by default, or a fixed-capacity
+/// [`heapless::Deque`] bounded by `CAP` when the `no_std` feature is enabled.
+#[cfg(not(feature = "no_std"))]
+type Pairs<'de, const CAP: usize> = VecDeque<Pair<'de>>;
+#[cfg(feature = "no_std")]
+type Pairs<'de, const CAP: usize> = Deque<Pair<'de>, CAP>;
+
+/// What shape the deserializer expected to find at a given key path.
+///
+/// Attached to [`Error::Expected`] so a failure on e.g. `a[b][c]=x` can say exactly which
+/// segment didn't hold the shape the target type asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpectedKind {
+    Map,
+    Seq,
+    Scalar,
+}
+
+impl std::fmt::Display for ExpectedKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ExpectedKind::Map => "map",
+            ExpectedKind::Seq => "seq",
+            ExpectedKind::Scalar => "scalar",
+        })
+    }
+}
+
+pub(crate) struct PairMap<'de, const CAP: usize = 32> {
+    pairs: Pairs<'de, CAP>,
     value: Option<&'de [u8]>,
-    stash: Stash<'de>,
+    stash: Stash<'de, CAP>,
+    unescape: bool,
+    path: Vec<Box<str>>,
+    last_key: Option<Box<str>>,
+    strict: bool,
+    seen_keys: Vec<Box<str>>,
 }
 
-impl<'de> PairMap<'de> {
+impl<'de, const CAP: usize> PairMap<'de, CAP> {
+    #[cfg(not(feature = "no_std"))]
     pub(crate) fn new(depth: u16, pairs: VecDeque<Pair<'de>>) -> Self {
         Self {
             pairs,
             value: None,
             stash: Stash::new(depth),
+            unescape: true,
+            path: Vec::new(),
+            last_key: None,
+            strict: false,
+            seen_keys: Vec::new(),
         }
     }
 
-    pub(crate) fn with_one_pair(depth: u16, pair: Pair<'de>) -> Self {
+    #[cfg(feature = "no_std")]
+    pub(crate) fn new(depth: u16, pairs: Deque<Pair<'de>, CAP>) -> Self {
+        Self {
+            pairs,
+            value: None,
+            stash: Stash::new(depth),
+            unescape: true,
+            path: Vec::new(),
+            last_key: None,
+            strict: false,
+            seen_keys: Vec::new(),
+        }
+    }
+
+    #[cfg(not(feature = "no_std"))]
+    pub(crate) fn with_one_pair(depth: u16, pair: Pair<'de>) -> Result<Self> {
         let mut pairs = VecDeque::new();
         pairs.push_front(pair);
-        Self {
+        Ok(Self {
             pairs,
             value: None,
             stash: Stash::new(depth),
+            unescape: true,
+            path: Vec::new(),
+            last_key: None,
+            strict: false,
+            seen_keys: Vec::new(),
+        })
+    }
+
+    #[cfg(feature = "no_std")]
+    pub(crate) fn with_one_pair(depth: u16, pair: Pair<'de>) -> Result<Self> {
+        let mut pairs = Deque::new();
+        pairs
+            .push_front(pair)
+            .map_err(|_| <Error as de::Error>::invalid_length(1, &"at most CAP pairs in a no_std PairMap"))?;
+        Ok(Self {
+            pairs,
+            value: None,
+            stash: Stash::new(depth),
+            unescape: true,
+            path: Vec::new(),
+            last_key: None,
+            strict: false,
+            seen_keys: Vec::new(),
+        })
+    }
+
+    /// Toggles ServerQuery escape decoding for keys and values produced by this map.
+    ///
+    /// Useful when the caller already fed in pre-decoded data and the `\s`/`\p`/... escape
+    /// table would otherwise be applied a second time.
+    pub(crate) fn unescape(mut self, unescape: bool) -> Self {
+        self.unescape = unescape;
+        self
+    }
+
+    /// Sets the accumulated key path leading to this map, so errors raised while deserializing
+    /// it can report e.g. `a[b][c]` instead of just `c`.
+    pub(crate) fn with_path(mut self, path: Vec<Box<str>>) -> Self {
+        self.path = path;
+        self
+    }
+
+    /// Toggles strict mode: duplicate keys at one map level and pairs left over once the
+    /// target type stops asking for keys become errors instead of last-wins / silently ignored.
+    pub(crate) fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Renders a key path as `a[b][c]`, the same bracket notation the crate parses on the way in.
+    fn render_path(path: &[Box<str>]) -> String {
+        let mut out = String::new();
+        for (index, segment) in path.iter().enumerate() {
+            if index == 0 {
+                out.push_str(segment);
+            } else {
+                out.push('[');
+                out.push_str(segment);
+                out.push(']');
+            }
+        }
+        out
+    }
+
+    /// The path a child map reached through `last_key` would have.
+    fn child_path(&self) -> Vec<Box<str>> {
+        let mut path = self.path.clone();
+        if let Some(key) = &self.last_key {
+            path.push(key.clone());
+        }
+        path
+    }
+
+    /// The path to the value currently being read, i.e. this map's path plus the last key.
+    fn child_path_string(&self) -> String {
+        Self::render_path(&self.child_path())
+    }
+
+    /// The path to this map itself, without any key that hasn't resolved to a value yet.
+    fn path_string(&self) -> String {
+        Self::render_path(&self.path)
+    }
+
+    fn decode<'a>(&self, bytes: &'a [u8]) -> Cow<'a, [u8]> {
+        if self.unescape {
+            unescape(bytes)
+        } else {
+            Cow::Borrowed(bytes)
+        }
+    }
+
+    /// In strict mode, rejects a key already seen at this map level instead of letting the
+    /// later pair win; outside strict mode this is a no-op.
+    fn check_duplicate(&mut self, key: &str) -> Result<()> {
+        if !self.strict {
+            return Ok(());
+        }
+
+        if self.seen_keys.iter().any(|seen| &**seen == key) {
+            return Err(Error::DuplicateKey {
+                key: key.to_owned(),
+            });
+        }
+
+        self.seen_keys.push(key.into());
+        Ok(())
+    }
+
+    /// In strict mode, checks that every pair and stashed entry at this map level was consumed
+    /// by the target type, erroring with the leftover keys instead of silently dropping them.
+    fn assert_exhausted(&mut self) -> Result<()> {
+        let mut keys = Vec::new();
+
+        while let Some(pair) = self.pairs.pop_back() {
+            keys.push(String::from_utf8_lossy(pair.key).into_owned());
+        }
+
+        while let Some(key) = self.stash.next_key()? {
+            keys.push(String::from_utf8_lossy(key).into_owned());
+            self.stash.next_value_map()?;
+        }
+
+        if keys.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::TrailingPairs { keys })
         }
     }
 
@@ -51,7 +236,9 @@ impl<'de> PairMap<'de> {
         }
 
         if !key_found {
-            return Err(Error::InvalidMapKey);
+            return Err(Error::InvalidMapKey {
+                path: self.path_string(),
+            });
         }
 
         if pair.key.len() > key_index + 1 {
@@ -64,7 +251,9 @@ impl<'de> PairMap<'de> {
                 Ok(None)
             } else {
                 // Cases like a[b]c=2 are invalid
-                Err(Error::InvalidMapKey)
+                Err(Error::InvalidMapKey {
+                    path: self.path_string(),
+                })
             }
         } else {
             self.value = Some(pair.value);
@@ -72,7 +261,9 @@ impl<'de> PairMap<'de> {
         }
     }
 
-    pub(crate) fn next_key(&mut self) -> Result<Option<&'de [u8]>> {
+    // Named `pop_key`/`pop_value` rather than `next_key`/`next_value` so they can't be shadowed
+    // by `MapAccess`'s own default methods of those names where `&mut PairMap` implements it.
+    pub(crate) fn pop_key(&mut self) -> Result<Option<&'de [u8]>> {
         loop {
             match self.pairs.pop_back() {
                 Some(pair) => match self.parse_pair(pair)? {
@@ -88,67 +279,150 @@ impl<'de> PairMap<'de> {
         }
     }
 
-    pub(crate) fn next_value(&mut self) -> Result<&'de [u8]> {
+    pub(crate) fn pop_value(&mut self) -> Result<&'de [u8]> {
         match self.value.take() {
             Some(value) => Ok(value),
-            None => Err(Error::InvalidMapValue),
+            None => Err(Error::Expected {
+                kind: ExpectedKind::Scalar,
+                path: self.child_path_string(),
+            }),
         }
     }
 
-    pub(crate) fn into_seq(mut self) -> Result<PairSeq<'de>> {
-        let mut items = vec![];
+    pub(crate) fn into_seq(mut self) -> Result<PairSeq<'de, CAP>> {
+        let own_path = self.path_string();
+
+        #[cfg(not(feature = "no_std"))]
+        let mut items: Vec<(isize, ItemKind<'de, CAP>)> = Vec::new();
+        #[cfg(feature = "no_std")]
+        let mut items: heapless::Vec<(isize, ItemKind<'de, CAP>), CAP> = heapless::Vec::new();
+
+        // Indexed entries (`a[0]=...`) use their parsed `u16` index; everything else (bare
+        // values, named groups like `a[foo][x]=...`) is appended in insertion order under -1.
+        // Duplicate indices are rejected rather than letting the later one silently win, and
+        // gaps between indices are allowed -- the sequence is simply sparse there.
+        let mut push = |index: isize, kind: ItemKind<'de, CAP>| -> Result<()> {
+            if index >= 0 && items.iter().any(|(seen, _)| *seen == index) {
+                return Err(<Error as de::Error>::custom(format_args!(
+                    "duplicate sequence index {index} at `{own_path}`"
+                )));
+            }
+
+            let item = (index, kind);
+            #[cfg(not(feature = "no_std"))]
+            {
+                items.push(item);
+                Ok(())
+            }
+            #[cfg(feature = "no_std")]
+            {
+                items
+                    .push(item)
+                    .map_err(|_| <Error as de::Error>::invalid_length(items.len() + 1, &"at most CAP sequence items"))
+            }
+        };
 
         // Pushing all pairs with empty keys as sequence values
-        while let Some(key) = self.next_key()? {
+        while let Some(key) = self.pop_key()? {
             if let Ok(index) = crate::from_bytes::<u16>(key) {
-                items.push((index as isize, ItemKind::Value(self.next_value()?)));
+                push(index as isize, ItemKind::Value(self.pop_value()?))?;
             } else {
-                items.push((-1, ItemKind::Value(self.next_value()?)));
+                push(-1, ItemKind::Value(self.pop_value()?))?;
             }
         }
 
-        // Pushing all pairs with non-empty keys as sequence sub maps
-        // TODO: support ordered sequence
+        // Pushing all pairs with non-empty keys as sequence sub maps. A parent may be indexed
+        // (`a[0][x]=...`, `a[1][x]=...`) or grouped by name (`a[foo][x]=...`), but not both --
+        // mixing the two under the same parent is rejected instead of silently misordered.
+        let mut saw_indexed_group = false;
+        let mut saw_named_group = false;
         while let Some(key) = self.stash.next_key()? {
-            let mut map = self.stash.next_value_map()?;
+            let mut map = self
+                .stash
+                .next_value_map()?
+                .unescape(self.unescape)
+                .strict(self.strict)
+                .with_path(self.path.clone());
             if key.is_empty() {
                 // We don't support anything but raw values for empty keys
                 // so we visit them one by one seprately
                 while let Some(pair) = map.pairs.pop_back() {
-                    items.push((
+                    push(
                         -1,
-                        ItemKind::Map(PairMap::with_one_pair(self.stash.remaining_depth - 1, pair)),
-                    ));
+                        ItemKind::Map(
+                            PairMap::with_one_pair(self.stash.remaining_depth - 1, pair)?
+                                .unescape(self.unescape)
+                                .strict(self.strict)
+                                .with_path(self.path.clone()),
+                        ),
+                    )?;
                 }
+            } else if let Ok(index) = crate::from_bytes::<u16>(key) {
+                saw_indexed_group = true;
+                if saw_named_group {
+                    return Err(<Error as de::Error>::custom(format_args!(
+                        "cannot mix indexed and named sequence groups under the same parent at `{own_path}`"
+                    )));
+                }
+                push(index as isize, ItemKind::Map(map))?;
             } else {
-                // Keys may be a group name in unordered sequence, or numbers for ordered ones
-                // so we should check that
-                if let Ok(index) = crate::from_bytes::<u16>(key) {
-                    items.push((index as isize, ItemKind::Map(map)));
-                } else {
-                    items.push((-1, ItemKind::Map(map)));
+                saw_named_group = true;
+                if saw_indexed_group {
+                    return Err(<Error as de::Error>::custom(format_args!(
+                        "cannot mix indexed and named sequence groups under the same parent at `{own_path}`"
+                    )));
                 }
+                push(-1, ItemKind::Map(map))?;
             }
         }
 
-        // Order the items by their keys
+        // Order the items by their keys. The `-1` bucket (named groups and raw unkeyed
+        // values) deliberately allows repeats, so the sort must be stable there or their
+        // relative order could be shuffled. `sort_by_key` gives that on the `std` path; the
+        // `no_std` path can't allocate for it, so it uses an in-place insertion sort, which is
+        // stable as well.
+        #[cfg(not(feature = "no_std"))]
         items.sort_by_key(|item| item.0);
+        #[cfg(feature = "no_std")]
+        for i in 1..items.len() {
+            let mut j = i;
+            while j > 0 && items[j - 1].0 > items[j].0 {
+                items.swap(j - 1, j);
+                j -= 1;
+            }
+        }
         items.reverse();
-        let items = items.into_iter().map(|item| item.1).collect();
 
-        Ok(PairSeq::new(items, self.stash.remaining_depth))
+        #[cfg(not(feature = "no_std"))]
+        let ordered: Vec<ItemKind<'de, CAP>> = items.into_iter().map(|item| item.1).collect();
+        #[cfg(feature = "no_std")]
+        let ordered: heapless::Vec<ItemKind<'de, CAP>, CAP> = {
+            let mut ordered = heapless::Vec::new();
+            for item in items {
+                ordered
+                    .push(item.1)
+                    .map_err(|_| <Error as de::Error>::invalid_length(ordered.len() + 1, &"at most CAP sequence items"))?;
+            }
+            ordered
+        };
+
+        Ok(PairSeq::new(ordered, self.stash.remaining_depth, self.unescape))
     }
 }
 
-impl<'de> de::Deserializer<'de> for PairMap<'de> {
+impl<'de, const CAP: usize> de::Deserializer<'de> for PairMap<'de, CAP> {
     type Error = Error;
 
     #[inline]
-    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
+    fn deserialize_any<V>(mut self, visitor: V) -> Result<V::Value>
     where
         V: de::Visitor<'de>,
     {
-        visitor.visit_map(self)
+        let value = visitor.visit_map(&mut self)?;
+        if self.strict {
+            self.assert_exhausted()?;
+        }
+        Ok(value)
     }
 
     #[inline]
@@ -191,7 +465,7 @@ impl<'de> de::Deserializer<'de> for PairMap<'de> {
     }
 }
 
-impl<'de> de::MapAccess<'de> for PairMap<'de> {
+impl<'de, const CAP: usize> de::MapAccess<'de> for &mut PairMap<'de, CAP> {
     type Error = Error;
 
     fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
@@ -200,18 +474,30 @@ impl<'de> de::MapAccess<'de> for PairMap<'de> {
     {
         // Calling next_value before next_key is an error, so we don't check the depth there
         if self.stash.remaining_depth == 0 {
-            return Err(Error::MaximumDepthReached);
+            return Err(Error::MaximumDepthReached {
+                path: self.path_string(),
+            });
         }
 
-        if let Some(key) = self.next_key()? {
-            return seed.deserialize(&mut Deserializer::new(key)).map(Some);
+        if let Some(key) = self.pop_key()? {
+            let decoded = self.decode(key);
+            let key_string = String::from_utf8_lossy(&decoded).into_owned();
+            self.check_duplicate(&key_string)?;
+            self.last_key = Some(key_string.into_boxed_str());
+            return seed.deserialize(&mut Deserializer::new(decoded)).map(Some);
         }
 
         // Visit stash
         let key = self.stash.next_key()?;
 
         match key {
-            Some(key) => seed.deserialize(&mut Deserializer::new(&key)).map(Some),
+            Some(key) => {
+                let decoded = self.decode(key);
+                let key_string = String::from_utf8_lossy(&decoded).into_owned();
+                self.check_duplicate(&key_string)?;
+                self.last_key = Some(key_string.into_boxed_str());
+                seed.deserialize(&mut Deserializer::new(decoded)).map(Some)
+            }
             None => Ok(None),
         }
     }
@@ -220,17 +506,26 @@ impl<'de> de::MapAccess<'de> for PairMap<'de> {
     where
         V: de::DeserializeSeed<'de>,
     {
-        match self.next_value() {
+        match self.pop_value() {
             Ok(value) => seed.deserialize(&mut Deserializer::new_with_depth(
-                value,
+                self.decode(value),
                 self.stash.remaining_depth - 1,
             )),
-            _ => seed.deserialize(self.stash.next_value_map()?),
+            _ => {
+                let path = self.child_path();
+                seed.deserialize(
+                    self.stash
+                        .next_value_map()?
+                        .unescape(self.unescape)
+                        .strict(self.strict)
+                        .with_path(path),
+                )
+            }
         }
     }
 }
 
-impl<'de> de::EnumAccess<'de> for &mut PairMap<'de> {
+impl<'de, const CAP: usize> de::EnumAccess<'de> for &mut PairMap<'de, CAP> {
     type Error = Error;
     type Variant = Self;
 
@@ -240,20 +535,31 @@ impl<'de> de::EnumAccess<'de> for &mut PairMap<'de> {
     {
         // Calling next_value before next_key is an error, so we don't check the depth there
         if self.stash.remaining_depth == 0 {
-            return Err(Error::MaximumDepthReached);
+            return Err(Error::MaximumDepthReached {
+                path: self.path_string(),
+            });
         }
 
         let key = {
-            if let Some(key) = self.next_key()? {
-                key
+            if let Some(key) = self.pop_key()? {
+                let decoded = self.decode(key);
+                self.last_key = Some(String::from_utf8_lossy(&decoded).into_owned().into_boxed_str());
+                decoded
             } else {
                 // Visit stash
                 let key = self.stash.next_key()?;
 
                 match key {
-                    Some(key) => key,
+                    Some(key) => {
+                        let decoded = self.decode(key);
+                        self.last_key =
+                            Some(String::from_utf8_lossy(&decoded).into_owned().into_boxed_str());
+                        decoded
+                    }
                     None => {
-                        return Err(Error::EofReached);
+                        return Err(Error::EofReached {
+                            path: self.path_string(),
+                        });
                     }
                 }
             }
@@ -269,7 +575,7 @@ impl<'de> de::EnumAccess<'de> for &mut PairMap<'de> {
     }
 }
 
-impl<'de> de::VariantAccess<'de> for &mut PairMap<'de> {
+impl<'de, const CAP: usize> de::VariantAccess<'de> for &mut PairMap<'de, CAP> {
     type Error = Error;
 
     #[inline]
@@ -281,12 +587,22 @@ impl<'de> de::VariantAccess<'de> for &mut PairMap<'de> {
     where
         V: de::Visitor<'de>,
     {
-        match self.next_value() {
+        match self.pop_value() {
             Ok(value) => {
-                let mut de = Deserializer::new_with_depth(value, self.stash.remaining_depth - 1);
+                let mut de =
+                    Deserializer::new_with_depth(self.decode(value), self.stash.remaining_depth - 1);
                 serde::de::Deserializer::deserialize_seq(&mut de, visitor)
             }
-            _ => visitor.visit_seq(&mut self.stash.next_value_map()?.into_seq()?),
+            _ => {
+                let path = self.child_path();
+                let map = self
+                    .stash
+                    .next_value_map()?
+                    .unescape(self.unescape)
+                    .strict(self.strict)
+                    .with_path(path);
+                visitor.visit_seq(&mut map.into_seq()?)
+            }
         }
     }
 
@@ -294,19 +610,35 @@ impl<'de> de::VariantAccess<'de> for &mut PairMap<'de> {
     where
         V: de::Visitor<'de>,
     {
-        visitor.visit_map(self.stash.next_value_map()?)
+        let path = self.child_path();
+        let mut map = self
+            .stash
+            .next_value_map()?
+            .unescape(self.unescape)
+            .strict(self.strict)
+            .with_path(path);
+        visitor.visit_map(&mut map)
     }
 
     fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value>
     where
         T: de::DeserializeSeed<'de>,
     {
-        match self.next_value() {
+        match self.pop_value() {
             Ok(value) => seed.deserialize(&mut Deserializer::new_with_depth(
-                value,
+                self.decode(value),
                 self.stash.remaining_depth - 1,
             )),
-            _ => seed.deserialize(self.stash.next_value_map()?),
+            _ => {
+                let path = self.child_path();
+                seed.deserialize(
+                    self.stash
+                        .next_value_map()?
+                        .unescape(self.unescape)
+                        .strict(self.strict)
+                        .with_path(path),
+                )
+            }
         }
     }
 }
@@ -1,29 +1,41 @@
+use std::borrow::Cow;
+
 use serde::de;
 
+use super::super::escape::unescape;
 use super::map::PairMap;
 use crate::de::Deserializer;
 use crate::error::{Error, Result};
 
-pub(crate) enum ItemKind<'de> {
+pub(crate) enum ItemKind<'de, const CAP: usize = 32> {
     Value(&'de [u8]),
-    Map(PairMap<'de>),
+    Map(PairMap<'de, CAP>),
 }
 
-pub(crate) struct PairSeq<'de> {
-    items: Vec<ItemKind<'de>>,
+/// The item backing store for a [`PairSeq`]: an unbounded [`Vec`] by default, or a fixed-capacity
+/// [`heapless::Vec`] bounded by `CAP` when the `no_std` feature is enabled.
+#[cfg(not(feature = "no_std"))]
+type Items<'de, const CAP: usize> = Vec<ItemKind<'de, CAP>>;
+#[cfg(feature = "no_std")]
+type Items<'de, const CAP: usize> = heapless::Vec<ItemKind<'de, CAP>, CAP>;
+
+pub(crate) struct PairSeq<'de, const CAP: usize = 32> {
+    items: Items<'de, CAP>,
     remaining_depth: u16,
+    unescape: bool,
 }
 
-impl<'de> PairSeq<'de> {
-    pub(crate) fn new(items: Vec<ItemKind<'de>>, remaining_depth: u16) -> Self {
+impl<'de, const CAP: usize> PairSeq<'de, CAP> {
+    pub(crate) fn new(items: Items<'de, CAP>, remaining_depth: u16, unescape: bool) -> Self {
         Self {
             items,
             remaining_depth,
+            unescape,
         }
     }
 }
 
-impl<'de> de::SeqAccess<'de> for PairSeq<'de> {
+impl<'de, const CAP: usize> de::SeqAccess<'de> for PairSeq<'de, CAP> {
     type Error = Error;
 
     fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
@@ -31,12 +43,18 @@ impl<'de> de::SeqAccess<'de> for PairSeq<'de> {
         T: de::DeserializeSeed<'de>,
     {
         match self.items.pop() {
-            Some(ItemKind::Value(value)) => seed
-                .deserialize(&mut Deserializer::new_with_depth(
+            Some(ItemKind::Value(value)) => {
+                let value = if self.unescape {
+                    unescape(value)
+                } else {
+                    Cow::Borrowed(value)
+                };
+                seed.deserialize(&mut Deserializer::new_with_depth(
                     value,
                     self.remaining_depth,
                 ))
-                .map(Some),
+                .map(Some)
+            }
             Some(ItemKind::Map(map)) => seed.deserialize(map).map(Some),
             None => Ok(None),
         }
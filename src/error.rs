@@ -0,0 +1,79 @@
+use std::fmt;
+
+use serde::de;
+
+pub use crate::de::stash::map::ExpectedKind;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Everything that can go wrong deserializing a ServerQuery key=value byte string.
+#[derive(Debug)]
+pub enum Error {
+    /// A key is missing its closing `]`, or has trailing bytes after one (`a[b]c=2`).
+    InvalidMapKey { path: String },
+    /// A bracketed key path (`a[b][c]...`) nests deeper than the deserializer allows.
+    MaximumDepthReached { path: String },
+    /// The input ran out while a variant name was still expected.
+    EofReached { path: String },
+    /// The shape found at `path` didn't match what the target type asked for.
+    ///
+    /// Displayed as `expected <kind> at <path>`, e.g. `expected scalar at a[b][c]`.
+    Expected { kind: ExpectedKind, path: String },
+    /// `key` repeated within the same map level while deserializing in strict mode.
+    DuplicateKey { key: String },
+    /// Pairs the target type never consumed, reported in strict mode instead of ignored.
+    TrailingPairs { keys: Vec<String> },
+    /// Any other error, carrying the message built by `serde::de::Error::custom`.
+    Custom(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidMapKey { path } => write!(f, "invalid map key at `{path}`"),
+            Error::MaximumDepthReached { path } => {
+                write!(f, "maximum nesting depth reached at `{path}`")
+            }
+            Error::EofReached { path } => write!(f, "unexpected end of input at `{path}`"),
+            Error::Expected { kind, path } => write!(f, "expected {kind} at {path}"),
+            Error::DuplicateKey { key } => write!(f, "duplicate key `{key}`"),
+            Error::TrailingPairs { keys } => {
+                write!(f, "unconsumed pairs: {}", keys.join(", "))
+            }
+            Error::Custom(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl de::Error for Error {
+    fn custom<T>(msg: T) -> Self
+    where
+        T: fmt::Display,
+    {
+        Error::Custom(msg.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expected_display_matches_the_documented_format() {
+        let error = Error::Expected {
+            kind: ExpectedKind::Scalar,
+            path: "a[b][c]".to_owned(),
+        };
+        assert_eq!(error.to_string(), "expected scalar at a[b][c]");
+    }
+
+    #[test]
+    fn trailing_pairs_lists_every_leftover_key() {
+        let error = Error::TrailingPairs {
+            keys: vec!["a".to_owned(), "b".to_owned()],
+        };
+        assert_eq!(error.to_string(), "unconsumed pairs: a, b");
+    }
+}
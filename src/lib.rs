@@ -0,0 +1,24 @@
+//! Deserialize TeamSpeak ServerQuery `key=value key2=value2` byte strings into Rust types.
+
+mod de;
+mod error;
+
+pub use crate::de::records::from_bytes_seq;
+pub use crate::error::{Error, ExpectedKind, Result};
+
+/// Deserializes a ServerQuery `key=value key2=value2` byte string into `T`.
+pub fn from_bytes<'de, T>(input: &'de [u8]) -> Result<T>
+where
+    T: serde::Deserialize<'de>,
+{
+    crate::de::from_bytes_with(input, false)
+}
+
+/// Like [`from_bytes`], but rejects any pair the target type never asked for and any
+/// duplicate key at the same map level, instead of silently ignoring or overwriting it.
+pub fn from_bytes_strict<'de, T>(input: &'de [u8]) -> Result<T>
+where
+    T: serde::Deserialize<'de>,
+{
+    crate::de::from_bytes_with(input, true)
+}